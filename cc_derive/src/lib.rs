@@ -0,0 +1,262 @@
+//! `#[derive(CyclicReference)]` for the `cc` cycle collector.
+//!
+//! Hand-writing `trace`/`trace_mut`/`break_references` for every type is error-prone: forget one
+//! field and you leak that edge. This macro derives the `cc::CyclicReference` impl for structs and
+//! enums, tracing every field whose type looks like an owning smart pointer (`Rc<RefCell<_>>`,
+//! `Option<_>`, `Box<_>`). `trace` and `trace_mut` each borrow every traced field in turn, the same
+//! zero-allocation way `Rc<RefCell<_>>`'s own impls do — `trace_mut` is what lets `collect_white`
+//! reach past the root of a derived type to break a child's references too, rather than stopping at
+//! the allocating `get_references`-based default — and `break_references` empties each field (`None`
+//! for `Option`, otherwise `Default::default()`). `Weak<_>` fields are never traced: a back-reference
+//! that's merely observing a cycle some other owner already traces shouldn't be treated as an owning
+//! edge, so there's no need to annotate it with `#[cc(skip)]` either. A field annotated
+//! `#[cc(skip)]` is left out of `trace`, `trace_mut`, and `break_references`, for the remaining cases
+//! where an owning-looking field still shouldn't be treated as one. `get_id` is derived as `None`,
+//! leaving the `Rc<RefCell<_>>` wrapper to supply the address.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use syn::{Data, DeriveInput, Field, Fields, Ident, Index};
+
+#[proc_macro_derive(CyclicReference, attributes(cc))]
+pub fn derive_cyclic_reference(input: TokenStream) -> TokenStream {
+    let input: DeriveInput = syn::parse(input).expect("cc_derive: could not parse input");
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let trace = trace_body(&input);
+    let trace_mut = trace_mut_body(&input);
+    let break_references = break_references_body(&input);
+
+    let expanded = quote! {
+        impl #impl_generics ::cc::CyclicReference for #name #ty_generics #where_clause {
+            fn trace(&self, tracer: &mut ::cc::Tracer) {
+                #trace
+            }
+
+            fn trace_mut(&mut self, tracer: &mut ::cc::TracerMut) {
+                #trace_mut
+            }
+
+            fn break_references(&mut self) -> bool {
+                #break_references
+            }
+
+            fn get_id(&self) -> Option<uint> { None }
+        }
+    };
+
+    expanded.into()
+}
+
+/// Whether `field` should be traced: not `#[cc(skip)]`, and typed like an owning handle.
+fn traced(field: &Field) -> bool {
+    !is_skipped(field) && is_traceable(field)
+}
+
+fn is_skipped(field: &Field) -> bool {
+    field.attrs.iter().any(|attr| {
+        if !attr.path.is_ident("cc") { return false }
+        // Accept the `#[cc(skip)]` form; anything else under `cc` is ignored here.
+        match attr.parse_args::<Ident>() {
+            Ok(ref ident) => ident == "skip",
+            Err(_) => false,
+        }
+    })
+}
+
+/// Trace fields whose outermost type is one of the owning wrappers this crate understands.
+///
+/// `Weak` is deliberately absent: it doesn't own its target, so treating it as traceable would
+/// mean upgrading it just to walk the graph, manufacturing a strong reference the object doesn't
+/// really have. A `Weak` back-reference is excluded from tracing automatically, the same as a
+/// `#[cc(skip)]` field.
+fn is_traceable(field: &Field) -> bool {
+    match field.ty {
+        syn::Type::Path(ref path) => match path.path.segments.last() {
+            Some(segment) => {
+                let head = &segment.ident;
+                head == "Rc" || head == "Option" || head == "Box"
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Whether the field's outermost type is `Option<_>`, which has `None` as its empty value.
+fn is_option(field: &Field) -> bool {
+    match field.ty {
+        syn::Type::Path(ref path) => match path.path.segments.last() {
+            Some(segment) => segment.ident == "Option",
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// The expression that empties a traced field: `None` for options, `Default::default()` otherwise.
+fn empty_value(field: &Field) -> proc_macro2::TokenStream {
+    if is_option(field) { quote!(None) } else { quote!(Default::default()) }
+}
+
+fn trace_body(input: &DeriveInput) -> proc_macro2::TokenStream {
+    match input.data {
+        Data::Struct(ref data) => {
+            let visits = field_accessors(&data.fields)
+                .into_iter()
+                .filter(|&(_, field)| traced(field))
+                .map(|(access, _)| quote!(tracer.visit(&self.#access);));
+            quote!(#(#visits)*)
+        }
+        Data::Enum(ref data) => {
+            let ename = &input.ident;
+            let arms = data.variants.iter().map(|variant| {
+                let vname = &variant.ident;
+                let (pattern, bindings) = bind_traced(&variant.fields);
+                let visits = bindings.iter().map(|ident| quote!(tracer.visit(#ident);));
+                quote!(#ename::#vname #pattern => { #(#visits)* })
+            });
+            quote! {
+                match *self {
+                    #(#arms),*
+                }
+            }
+        }
+        Data::Union(_) => panic!("cc_derive: unions are not supported"),
+    }
+}
+
+/// As `trace_body`, but binding each traced field mutably so `collect_white` can break references
+/// reached through a derived type, not just ones it's handed the root of directly.
+fn trace_mut_body(input: &DeriveInput) -> proc_macro2::TokenStream {
+    match input.data {
+        Data::Struct(ref data) => {
+            let visits = field_accessors(&data.fields)
+                .into_iter()
+                .filter(|&(_, field)| traced(field))
+                .map(|(access, _)| quote!(tracer.visit(&mut self.#access);));
+            quote!(#(#visits)*)
+        }
+        Data::Enum(ref data) => {
+            let ename = &input.ident;
+            let arms = data.variants.iter().map(|variant| {
+                let vname = &variant.ident;
+                let (pattern, bindings) = bind_traced_mut(&variant.fields);
+                let visits = bindings.iter().map(|&(ref ident, _)| quote!(tracer.visit(#ident);));
+                quote!(#ename::#vname #pattern => { #(#visits)* })
+            });
+            quote! {
+                match *self {
+                    #(#arms),*
+                }
+            }
+        }
+        Data::Union(_) => panic!("cc_derive: unions are not supported"),
+    }
+}
+
+fn break_references_body(input: &DeriveInput) -> proc_macro2::TokenStream {
+    match input.data {
+        Data::Struct(ref data) => {
+            let resets = field_accessors(&data.fields)
+                .into_iter()
+                .filter(|&(_, field)| traced(field))
+                .map(|(access, field)| {
+                    let empty = empty_value(field);
+                    quote!(self.#access = #empty;)
+                });
+            quote! {
+                #(#resets)*
+                true
+            }
+        }
+        Data::Enum(ref data) => {
+            let ename = &input.ident;
+            let arms = data.variants.iter().map(|variant| {
+                let vname = &variant.ident;
+                let (pattern, bindings) = bind_traced_mut(&variant.fields);
+                let resets = bindings.iter().map(|&(ref ident, field)| {
+                    let empty = empty_value(field);
+                    quote!(*#ident = #empty;)
+                });
+                quote!(#ename::#vname #pattern => { #(#resets)* })
+            });
+            quote! {
+                match *self {
+                    #(#arms),*
+                }
+                true
+            }
+        }
+        Data::Union(_) => panic!("cc_derive: unions are not supported"),
+    }
+}
+
+/// Field access expressions (`foo` or `0`) paired with their fields, for struct bodies.
+fn field_accessors(fields: &Fields) -> Vec<(proc_macro2::TokenStream, &Field)> {
+    match *fields {
+        Fields::Named(ref named) => named.named.iter().map(|field| {
+            let ident = field.ident.as_ref().unwrap();
+            (quote!(#ident), field)
+        }).collect(),
+        Fields::Unnamed(ref unnamed) => unnamed.unnamed.iter().enumerate().map(|(i, field)| {
+            let index = Index::from(i);
+            (quote!(#index), field)
+        }).collect(),
+        Fields::Unit => Vec::new(),
+    }
+}
+
+/// Build a match pattern binding just the traced fields of a variant by shared reference, plus the
+/// list of binding idents in order.
+fn bind_traced(fields: &Fields) -> (proc_macro2::TokenStream, Vec<Ident>) {
+    bind(fields, false)
+}
+
+/// As `bind_traced`, but binding by mutable reference and pairing each ident with its field.
+fn bind_traced_mut(fields: &Fields) -> (proc_macro2::TokenStream, Vec<(Ident, &Field)>) {
+    let (pattern, idents) = bind(fields, true);
+    // Re-pair the bound idents with their fields in declaration order.
+    let traced_fields: Vec<&Field> = match *fields {
+        Fields::Named(ref n) => n.named.iter().filter(|f| traced(f)).collect(),
+        Fields::Unnamed(ref u) => u.unnamed.iter().filter(|f| traced(f)).collect(),
+        Fields::Unit => Vec::new(),
+    };
+    let paired = idents.into_iter().zip(traced_fields.into_iter()).collect();
+    (pattern, paired)
+}
+
+fn bind(fields: &Fields, mutable: bool) -> (proc_macro2::TokenStream, Vec<Ident>) {
+    let by_ref = if mutable { quote!(ref mut) } else { quote!(ref) };
+    match *fields {
+        Fields::Named(ref named) => {
+            let mut idents = Vec::new();
+            let binds = named.named.iter().filter(|f| traced(f)).map(|field| {
+                let ident = field.ident.clone().unwrap();
+                idents.push(ident.clone());
+                quote!(#ident: #by_ref #ident)
+            }).collect::<Vec<_>>();
+            (quote!({ #(#binds,)* .. }), idents)
+        }
+        Fields::Unnamed(ref unnamed) => {
+            let mut idents = Vec::new();
+            let binds = unnamed.unnamed.iter().enumerate().map(|(i, field)| {
+                if traced(field) {
+                    let ident = Ident::new(&format!("f{}", i), proc_macro2::Span::call_site());
+                    idents.push(ident.clone());
+                    quote!(#by_ref #ident)
+                } else {
+                    quote!(_)
+                }
+            }).collect::<Vec<_>>();
+            (quote!((#(#binds),*)), idents)
+        }
+        Fields::Unit => (quote!(), Vec::new()),
+    }
+}
@@ -27,6 +27,30 @@ impl Drop for List {
 }
 
 impl cc::CyclicReference for List {
+    // Borrow each child straight out of the `Pair` instead of cloning it. A clone would bump the
+    // child's `Rc::strong_count` for the duration of the trace, which is exactly the count
+    // `collect`'s orphan guard is trying to measure, so tracing must not itself manufacture an
+    // extra owner.
+    fn trace(&self, tracer: &mut cc::Tracer) {
+        match *self {
+            Pair(ref a, ref b) => {
+                tracer.visit(a);
+                tracer.visit(b);
+            }
+            _ => {}
+        }
+    }
+
+    fn trace_mut(&mut self, tracer: &mut cc::TracerMut) {
+        match *self {
+            Pair(ref mut a, ref mut b) => {
+                tracer.visit(a);
+                tracer.visit(b);
+            }
+            _ => {}
+        }
+    }
+
     fn get_references(&self) -> cc::RefList {
         match *self {
             Pair(ref a, ref b) => Some(vec![box a.clone() as Box<cc::CyclicReference>,
@@ -56,6 +80,7 @@ fn main() {
         _ => panic!()
     }
 
-    // a, and the clone of a inside of itself will both be freed.
+    // `a` holds a clone of itself plus the `Num` cell; once that self-cycle is recognized as
+    // orphaned, both `a` and the `Num` hanging off it are garbage, so two objects are broken.
     assert_eq!(cc::collect(&mut a), Some(2));
 }
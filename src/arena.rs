@@ -0,0 +1,208 @@
+//! A scope-bounded cycle collector that owns every object it hands out.
+//!
+//! Where the free-standing `collect` has to guess whether a discovered cycle is still reachable
+//! from outside, an arena `Collector` sidesteps the question: it keeps a strong reference to every
+//! object it allocates, and every `Handle` it returns is tied to the arena's lifetime, so no
+//! handle can outlive the collector. When the arena is dropped (or `collect_all` is called), it can
+//! therefore walk and break every object it owns, knowing that the only references left once the
+//! handles are gone are the arena's own and the ones inside cycles. This follows the ScopedRc
+//! design, turning the crate into a usable scoped GC region.
+
+use std::rc::Rc;
+use std::cell::{RefCell, Ref, RefMut};
+use std::ops::Deref;
+use std::marker::PhantomData;
+
+use {CyclicReference, Tracer, TracerMut, RefList};
+
+/// A handle to an arena-allocated object, tied to the arena's lifetime.
+///
+/// A `Handle` derefs to its backing `RefCell<T>` exactly like an `Rc` would, so the contents are
+/// reached through `borrow`/`borrow_mut`. Because it borrows the arena for `'arena`, it cannot
+/// outlive the collector that owns the object.
+pub struct Handle<'arena, T: CyclicReference + 'static> {
+    inner: Rc<RefCell<T>>,
+    _marker: PhantomData<&'arena ()>,
+}
+
+impl<'arena, T: CyclicReference + 'static> Handle<'arena, T> {
+    /// Immutably borrow the contents, as `RefCell::borrow`.
+    pub fn borrow(&self) -> Ref<T> { self.inner.borrow() }
+
+    /// Mutably borrow the contents, as `RefCell::borrow_mut`.
+    pub fn borrow_mut(&self) -> RefMut<T> { self.inner.borrow_mut() }
+}
+
+impl<'arena, T: CyclicReference + 'static> Deref for Handle<'arena, T> {
+    type Target = RefCell<T>;
+    fn deref(&self) -> &RefCell<T> { &*self.inner }
+}
+
+/// Forwards straight through to the wrapped `T`, so a `Handle` field on another arena object is
+/// itself a traceable edge: this is what lets two objects allocated through the same `Collector`
+/// hold handles to each other and actually form a cycle for `collect_all` to find.
+impl<'arena, T: CyclicReference + 'static> CyclicReference for Handle<'arena, T> {
+    fn break_references(&mut self) -> bool { self.inner.break_references() }
+    fn trace(&self, tracer: &mut Tracer) { self.inner.trace(tracer) }
+    fn trace_mut(&mut self, tracer: &mut TracerMut) { self.inner.trace_mut(tracer) }
+    fn get_references(&self) -> RefList { self.inner.get_references() }
+    fn get_id(&self) -> Option<uint> { self.inner.get_id() }
+    fn strong_count(&self) -> Option<uint> { self.inner.strong_count() }
+}
+
+impl<'arena, T: CyclicReference + 'static> Clone for Handle<'arena, T> {
+    fn clone(&self) -> Handle<'arena, T> {
+        Handle { inner: self.inner.clone(), _marker: PhantomData }
+    }
+}
+
+/// An arena that owns every object allocated through it and collects cycles on drop.
+pub struct Collector<T: CyclicReference + 'static> {
+    items: RefCell<Vec<Rc<RefCell<T>>>>,
+}
+
+impl<T: CyclicReference + 'static> Collector<T> {
+    /// Create an empty arena.
+    pub fn new() -> Collector<T> {
+        Collector { items: RefCell::new(Vec::new()) }
+    }
+
+    /// Allocate `value` in the arena, returning a `Handle` that cannot outlive it.
+    pub fn alloc<'a>(&'a self, value: T) -> Handle<'a, T> {
+        let inner = Rc::new(RefCell::new(value));
+        self.items.borrow_mut().push(inner.clone());
+        Handle { inner: inner, _marker: PhantomData }
+    }
+
+    /// Break every garbage cycle among the arena's objects, returning the number of objects whose
+    /// references were broken.
+    ///
+    /// An object that is kept alive by more than the arena's own reference plus the edges it
+    /// receives from other arena objects has a live `Handle` somewhere, so it — and everything
+    /// reachable from it — is spared. Everything else is unreachable from any handle, held only by
+    /// the arena and by other such objects (whether or not it sits in a cycle), and has its
+    /// references broken and counted, after which ordinary refcounting reclaims it.
+    ///
+    /// This walk keys `strong`/`incoming`/`adjacency` off `get_id`, so it only lines a child up
+    /// with the same entry `items` recorded for it if `get_id` identifies the shared allocation
+    /// rather than whatever handle happened to be borrowed (see the note on `CyclicReference::get_id`)
+    /// — otherwise a live object reachable only through another arena object's field would never
+    /// be marked live here.
+    pub fn collect_all(&self) -> u32 {
+        use std::collections::{HashMap, HashSet};
+
+        let items = self.items.borrow();
+
+        let mut strong: HashMap<uint, uint> = HashMap::new();
+        let mut incoming: HashMap<uint, uint> = HashMap::new();
+        let mut adjacency: HashMap<uint, Vec<uint>> = HashMap::new();
+
+        for item in items.iter() {
+            let id = match item.get_id() { Some(id) => id, None => continue };
+            strong.insert(id, item.strong_count().unwrap_or(0));
+            let mut children = Vec::new();
+            item.trace(&mut Tracer::new(&mut |child: &CyclicReference| {
+                match child.get_id() {
+                    Some(cid) => {
+                        children.push(cid);
+                        let n = incoming.get(&cid).map(|v| *v).unwrap_or(0);
+                        incoming.insert(cid, n + 1);
+                    }
+                    None => {}
+                }
+            }));
+            adjacency.insert(id, children);
+        }
+
+        // Seed the live set with the externally-owned objects, then flood to everything they reach.
+        let mut live: HashSet<uint> = HashSet::new();
+        let mut worklist = Vec::new();
+        for (id, count) in strong.iter() {
+            let in_set = incoming.get(id).map(|v| *v).unwrap_or(0);
+            if *count > in_set + 1 && live.insert(*id) {
+                worklist.push(*id);
+            }
+        }
+        loop {
+            let id = match worklist.pop() { Some(id) => id, None => break };
+            match adjacency.get(&id) {
+                Some(children) => for child in children.iter() {
+                    if live.insert(*child) { worklist.push(*child) }
+                },
+                None => {}
+            }
+        }
+
+        let mut broken = 0;
+        for item in items.iter() {
+            let id = match item.get_id() { Some(id) => id, None => continue };
+            if !live.contains(&id) {
+                let mut handle = item.clone();
+                if handle.break_references() { broken += 1 }
+            }
+        }
+        broken
+    }
+}
+
+#[unsafe_destructor]
+impl<T: CyclicReference + 'static> Drop for Collector<T> {
+    fn drop(&mut self) {
+        // Every handle is gone by now, so one pass breaks the cycles and refcounting does the rest.
+        self.collect_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Collector, Handle};
+    use {CyclicReference, Tracer, TracerMut};
+
+    /// A node whose only owning edge is a `Handle` to another node, so two of these allocated
+    /// through the same arena can hold handles to each other and form a cycle.
+    struct Node {
+        next: Option<Handle<'static, Node>>,
+    }
+
+    impl CyclicReference for Node {
+        fn trace(&self, tracer: &mut Tracer) {
+            match self.next {
+                Some(ref n) => tracer.visit(n),
+                None => {}
+            }
+        }
+
+        fn trace_mut(&mut self, tracer: &mut TracerMut) {
+            match self.next {
+                Some(ref mut n) => tracer.visit(n),
+                None => {}
+            }
+        }
+
+        fn break_references(&mut self) -> bool {
+            self.next = None;
+            true
+        }
+
+        fn get_id(&self) -> Option<uint> { None }
+    }
+
+    #[test]
+    fn collect_all_breaks_a_cycle_built_through_handle() {
+        // `alloc` ties each `Handle` to the borrow of `&self`; a self-cycle through
+        // `Handle<'arena, Node>` would otherwise require `Node` itself to carry `'arena`, so leak
+        // the arena onto a `'static` borrow instead, the same way a self-referential typed arena
+        // normally would.
+        let collector: &'static Collector<Node> = Box::leak(Box::new(Collector::new()));
+        let a = collector.alloc(Node { next: None });
+        let b = collector.alloc(Node { next: None });
+        a.borrow_mut().next = Some(b.clone());
+        b.borrow_mut().next = Some(a.clone());
+
+        // Drop both handles: only the cycle keeps `a` and `b` alive now, so `collect_all` should
+        // find them unreachable and break both.
+        drop(a);
+        drop(b);
+        assert_eq!(collector.collect_all(), 2);
+    }
+}
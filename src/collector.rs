@@ -0,0 +1,187 @@
+//! A standalone cycle collector that tracks its own candidate roots.
+//!
+//! Rather than asking callers to hand a single root to `collect`, a `Collector` hands back a
+//! `Gc<T>` handle for every object it allocates and remembers a `Weak` pointer to each. When a
+//! handle is dropped while the object it points at is still alive, it may have just severed the
+//! last *acyclic* reference to a cycle, so the handle nominates the object as a candidate root by
+//! pushing its id onto a channel. `trigger` later drains those nominations, prunes the candidates
+//! that have since died, and runs the trial-deletion `collect` over what remains. This mirrors the
+//! samsara design and gives users automatic leak reclamation without tracking roots by hand.
+//!
+//! This module does not offer a dedicated background-collector thread, despite that being part of
+//! the original ask: `Collector<T>` and `Gc<T>` are built on `Rc`/`Weak`, which are `!Send`, so
+//! there is no way to move collection onto another thread without changing the underlying pointer
+//! type. `run` is therefore a same-thread event loop (see its own doc), not a background thread;
+//! that's a deliberate, acknowledged scope cut rather than an oversight.
+
+use std::rc::{Rc, Weak};
+use std::cell::{RefCell, Ref, RefMut};
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+use super::collect;
+use {CyclicReference, Tracer, TracerMut, RefList};
+
+/// A collector-managed handle to a `T`, wrapping `Rc<RefCell<T>>`.
+///
+/// A `Gc` reaches its contents through `borrow`/`borrow_mut`, just like the underlying
+/// `RefCell`. Dropping a `Gc` whose object still has other owners nominates that object as a
+/// possible cycle root with the owning `Collector`.
+pub struct Gc<T: CyclicReference + 'static> {
+    inner: Rc<RefCell<T>>,
+    candidates: Sender<uint>,
+}
+
+impl<T: CyclicReference + 'static> Gc<T> {
+    /// Immutably borrow the contents, as `RefCell::borrow`.
+    pub fn borrow(&self) -> Ref<T> { self.inner.borrow() }
+
+    /// Mutably borrow the contents, as `RefCell::borrow_mut`.
+    pub fn borrow_mut(&self) -> RefMut<T> { self.inner.borrow_mut() }
+
+    fn id(&self) -> uint { self.get_id().unwrap() }
+}
+
+/// Forwards straight through to the wrapped `T`, the same way `Box<R>` does, so a `Gc<T>` field on
+/// another collector-managed object is itself a traceable edge: this is what lets two objects
+/// registered through the same `Collector` hold clones of each other and actually form a cycle.
+impl<T: CyclicReference + 'static> CyclicReference for Gc<T> {
+    fn break_references(&mut self) -> bool { self.inner.break_references() }
+    fn trace(&self, tracer: &mut Tracer) { self.inner.trace(tracer) }
+    fn trace_mut(&mut self, tracer: &mut TracerMut) { self.inner.trace_mut(tracer) }
+    fn get_references(&self) -> RefList { self.inner.get_references() }
+    fn get_id(&self) -> Option<uint> { self.inner.get_id() }
+    fn strong_count(&self) -> Option<uint> { self.inner.strong_count() }
+}
+
+impl<T: CyclicReference + 'static> Clone for Gc<T> {
+    fn clone(&self) -> Gc<T> {
+        Gc { inner: self.inner.clone(), candidates: self.candidates.clone() }
+    }
+}
+
+#[unsafe_destructor]
+impl<T: CyclicReference + 'static> Drop for Gc<T> {
+    fn drop(&mut self) {
+        // If the object outlives this handle (its strong count is still above one, before our own
+        // `Rc` is released), losing this acyclic reference might have left only a cycle keeping it
+        // alive, so nominate it as a candidate root. A send failure just means the collector is
+        // already gone, which is fine.
+        if Rc::strong_count(&self.inner) > 1 {
+            let _ = self.candidates.send(self.id());
+        }
+    }
+}
+
+/// Owns the candidate list and runs cycle collection over it.
+pub struct Collector<T: CyclicReference + 'static> {
+    candidates: Vec<Weak<RefCell<T>>>,
+    sender: Sender<uint>,
+    receiver: Receiver<uint>,
+}
+
+impl<T: CyclicReference + 'static> Collector<T> {
+    /// Create an empty collector.
+    pub fn new() -> Collector<T> {
+        let (sender, receiver) = channel();
+        Collector { candidates: Vec::new(), sender: sender, receiver: receiver }
+    }
+
+    /// Allocate `value` under the collector, returning a `Gc` handle to it.
+    pub fn register(&mut self, value: T) -> Gc<T> {
+        let inner = Rc::new(RefCell::new(value));
+        self.candidates.push(Rc::downgrade(&inner));
+        Gc { inner: inner, candidates: self.sender.clone() }
+    }
+
+    /// Drain pending nominations, prune candidates that have since died, and run trial-deletion
+    /// collection over every candidate still alive. Returns the number of objects broken.
+    pub fn trigger(&mut self) -> u32 {
+        // The channel is only a wakeup signal here: every live candidate is reconsidered, so we
+        // just drain the backlog of nominated ids.
+        while self.receiver.try_recv().is_ok() {}
+
+        self.candidates.retain(|weak| weak.upgrade().is_some());
+
+        let mut broken = 0;
+        for weak in self.candidates.iter() {
+            match weak.upgrade() {
+                Some(mut strong) => match collect(&mut strong) {
+                    Some(n) => broken += n,
+                    None => {}
+                },
+                None => {}
+            }
+        }
+        broken
+    }
+
+    /// Body of a collector event loop: block until a handle nominates a candidate, then `trigger`,
+    /// repeating until every `Gc` has been dropped and the channel disconnects.
+    ///
+    /// `Collector<T>` holds `Rc`/`Weak`, which are `!Send`, so it can't be moved onto a separate
+    /// thread the way a true background collector would be; call this from the same thread that
+    /// owns the `Gc` handles instead. It blocks that thread until the last `Gc` is gone, so handles
+    /// still alive when it is called are only ever dropped by the `trigger`s it runs.
+    pub fn run(mut self) {
+        // Release the collector's own sender, so the channel disconnects once every `Gc` (each of
+        // which holds a clone of it) has been dropped.
+        let (detached, _) = channel();
+        self.sender = detached;
+        loop {
+            match self.receiver.recv() {
+                Ok(_) => { self.trigger(); }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Collector, Gc};
+    use {CyclicReference, Tracer, TracerMut};
+
+    /// A node whose only owning edge is a `Gc` to another node, so two of these registered through
+    /// the same `Collector` can hold clones of each other and form a cycle.
+    struct Node {
+        next: Option<Gc<Node>>,
+    }
+
+    impl CyclicReference for Node {
+        fn trace(&self, tracer: &mut Tracer) {
+            match self.next {
+                Some(ref n) => tracer.visit(n),
+                None => {}
+            }
+        }
+
+        fn trace_mut(&mut self, tracer: &mut TracerMut) {
+            match self.next {
+                Some(ref mut n) => tracer.visit(n),
+                None => {}
+            }
+        }
+
+        fn break_references(&mut self) -> bool {
+            self.next = None;
+            true
+        }
+
+        fn get_id(&self) -> Option<uint> { None }
+    }
+
+    #[test]
+    fn trigger_breaks_a_cycle_built_through_gc() {
+        let mut collector = Collector::new();
+        let a = collector.register(Node { next: None });
+        let b = collector.register(Node { next: None });
+        a.borrow_mut().next = Some(b.clone());
+        b.borrow_mut().next = Some(a.clone());
+
+        // Drop both outer handles: only the cycle keeps `a` and `b` alive now, so `trigger` should
+        // find them nominated and break both.
+        drop(a);
+        drop(b);
+        assert_eq!(collector.trigger(), 2);
+    }
+}
@@ -1,12 +1,55 @@
+#![feature(unsafe_destructor)]
+
 use std::rc::Rc;
 use std::cell::{RefCell, Ref, RefMut};
 
+pub mod collector;
+pub mod arena;
+
 pub type RefList = Option<Vec<Box<CyclicReference+'static>>>;
 
+/// A sink for the outgoing edges of a `CyclicReference`.
+///
+/// A `Tracer` wraps a callback that is invoked once per edge during a traversal. It lets the graph
+/// be walked without cloning every child into a boxed `Vec`, the way `get_references` must.
+pub struct Tracer<'a> {
+    callback: &'a mut (FnMut(&CyclicReference) + 'a),
+}
+
+impl<'a> Tracer<'a> {
+    /// Build a `Tracer` from a callback to run on each traced edge.
+    pub fn new(callback: &'a mut (FnMut(&CyclicReference) + 'a)) -> Tracer<'a> {
+        Tracer { callback: callback }
+    }
+
+    /// Hand one outgoing edge to the callback.
+    pub fn visit(&mut self, reference: &CyclicReference) {
+        (*self.callback)(reference)
+    }
+}
+
+/// As `Tracer`, but for walks that need to mutate each child in place (eg `collect_white` breaking
+/// references), so it hands out a mutable reference instead of a shared one.
+pub struct TracerMut<'a> {
+    callback: &'a mut (FnMut(&mut CyclicReference) + 'a),
+}
+
+impl<'a> TracerMut<'a> {
+    /// Build a `TracerMut` from a callback to run on each traced edge.
+    pub fn new(callback: &'a mut (FnMut(&mut CyclicReference) + 'a)) -> TracerMut<'a> {
+        TracerMut { callback: callback }
+    }
+
+    /// Hand one outgoing edge to the callback.
+    pub fn visit(&mut self, reference: &mut CyclicReference) {
+        (*self.callback)(reference)
+    }
+}
+
 /// A possibly-cyclic reference that should be considered during cycle collection.
 ///
-/// To break a cycle, `break_references` will be used. To walk the object graph, `get_references`
-/// will be used.
+/// To break a cycle, `break_references` will be used. To walk the object graph, `trace` will be
+/// used.
 pub trait CyclicReference {
     /// Break any nested references this reference might contain, to remove it from a cycle.
     ///
@@ -14,16 +57,59 @@ pub trait CyclicReference {
     /// made (due to not being able to acquire a RefCell or RWLock, for example).
     fn break_references(&mut self) -> bool;
 
-    /// Return any references referenced by this reference.
-    fn get_references(&self) -> RefList;
+    /// Trace each outgoing edge of this reference by handing it to `tracer`.
+    ///
+    /// This is how cycle collection walks the graph: every implementation must borrow each child
+    /// in turn and pass it to the tracer's callback, instead of cloning every child into a boxed
+    /// `Vec` the way the legacy `get_references` does. Borrowing matters for more than allocation:
+    /// `is_orphaned`'s reachability test compares live strong counts against in-set edges, and a
+    /// clone minted just to satisfy the walk would throw that comparison off by manufacturing an
+    /// owner that doesn't really exist (see `Rc<RefCell<R>>`'s impl below for the pattern to follow).
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// As `trace`, but for walks that need to mutate each child (`collect_white` breaking
+    /// references out from under a garbage cycle).
+    ///
+    /// Implementations that already override `trace` should give this the same treatment; the
+    /// default instead falls back to `get_references`, cloning each child the way the original
+    /// allocating walk did, which is fine here since `collect_white` runs after the trial-deletion
+    /// passes have already decided what to break and no longer cares about live strong counts.
+    fn trace_mut(&mut self, tracer: &mut TracerMut) {
+        match self.get_references() {
+            Some(refs) => for mut reference in refs.into_iter() { tracer.visit(&mut *reference) },
+            None => {}
+        }
+    }
+
+    /// Return any references referenced by this reference, as an allocated `Vec`.
+    ///
+    /// This is the legacy accessor `trace` replaces for cycle collection's own walks, which never
+    /// call it directly (only `trace_mut`'s default does, for the types that haven't been ported to
+    /// `trace_mut` yet). There's no general way to manufacture an owned, boxed clone of a child from
+    /// the borrow `trace` hands out, so the default simply reports nothing; implementations that
+    /// still want to expose this list to outside callers should override it directly, as `List`
+    /// does in `examples/simple.rs`.
+    fn get_references(&self) -> RefList { None }
 
     /// Get the id of this reference, used to determine whether this object has been seen
     /// before.
     ///
     /// It is intended that smart pointers will return their address here. If there is no useful id
     /// to return for a given implementation of CyclicReference, return None. Odds are, some
-    /// wrapper type higher up has you covered (eg, `Rc<RefCell<R>>`)
+    /// wrapper type higher up has you covered (eg, `Rc<RefCell<R>>`).
+    ///
+    /// This id must identify the shared heap allocation, not whichever particular handle happens
+    /// to be borrowed when it's asked for: the same object is reached through many different
+    /// `Rc` clones over the course of a walk, and if each clone reported a different id the
+    /// trial-deletion state machine below could never recognize that it had seen the object before.
     fn get_id(&self) -> Option<uint>;
+
+    /// Read the live strong reference count backing this object, if one is available.
+    ///
+    /// Trial deletion seeds its simulated reference counts from this value. As with `get_id`, a
+    /// bare implementation rarely has a meaningful count to report and should return `None`,
+    /// leaving it to a wrapper such as `Rc<RefCell<R>>` to hand back the real `Rc::strong_count`.
+    fn strong_count(&self) -> Option<uint> { None }
 }
 
 impl<R: CyclicReference> CyclicReference for Rc<RefCell<R>> {
@@ -31,85 +117,382 @@ impl<R: CyclicReference> CyclicReference for Rc<RefCell<R>> {
         self.try_borrow_mut().map(|mut r| r.break_references()).unwrap_or(false)
     }
 
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.try_borrow() {
+            Some(r) => r.trace(tracer),
+            None => {}
+        }
+    }
+
+    fn trace_mut(&mut self, tracer: &mut TracerMut) {
+        match self.try_borrow_mut() {
+            Some(mut r) => r.trace_mut(tracer),
+            None => {}
+        }
+    }
+
     fn get_references(&self) -> RefList {
         self.try_borrow().as_ref().and_then(|r| r.get_references())
     }
 
-    fn get_id(&self) -> Option<uint> { Some(&*self as *const _ as uint) }
+    fn get_id(&self) -> Option<uint> {
+        // Deref through the `Rc` to the heap-allocated `RefCell`, so every clone of this handle
+        // reports the same id. `&*self as *const _` would instead give the address of this
+        // particular `Rc` handle, which differs between clones of the same object.
+        Some(&**self as *const RefCell<R> as uint)
+    }
+
+    fn strong_count(&self) -> Option<uint> { Some(Rc::strong_count(self)) }
 }
 
 impl<'a, R: CyclicReference> CyclicReference for RefMut<'a, R> {
     fn break_references(&mut self) -> bool { (**self).break_references() }
+    fn trace(&self, tracer: &mut Tracer) { (**self).trace(tracer) }
+    fn trace_mut(&mut self, tracer: &mut TracerMut) { (**self).trace_mut(tracer) }
     fn get_references(&self) -> RefList { (**self).get_references() }
     fn get_id(&self) -> Option<uint> { (**self).get_id() }
+    fn strong_count(&self) -> Option<uint> { (**self).strong_count() }
 }
 
 impl<'a, R: CyclicReference> CyclicReference for &'a mut R {
     fn break_references(&mut self) -> bool { (**self).break_references() }
+    fn trace(&self, tracer: &mut Tracer) { (**self).trace(tracer) }
+    fn trace_mut(&mut self, tracer: &mut TracerMut) { (**self).trace_mut(tracer) }
     fn get_references(&self) -> RefList { (**self).get_references() }
     fn get_id(&self) -> Option<uint> { (**self).get_id() }
+    fn strong_count(&self) -> Option<uint> { (**self).strong_count() }
 }
 
 impl<'a, R: CyclicReference> CyclicReference for Ref<'a, R> {
     fn break_references(&mut self) -> bool { false }
+    fn trace(&self, tracer: &mut Tracer) { (**self).trace(tracer) }
     fn get_references(&self) -> RefList { (**self).get_references() }
     fn get_id(&self) -> Option<uint> { (**self).get_id() }
+    fn strong_count(&self) -> Option<uint> { (**self).strong_count() }
 }
 
 impl<'a, R: CyclicReference> CyclicReference for &'a R {
     fn break_references(&mut self) -> bool { false }
+    fn trace(&self, tracer: &mut Tracer) { (**self).trace(tracer) }
     fn get_references(&self) -> RefList { (**self).get_references() }
     fn get_id(&self) -> Option<uint> { (**self).get_id() }
+    fn strong_count(&self) -> Option<uint> { (**self).strong_count() }
 }
 
 impl<R: CyclicReference> CyclicReference for Option<R> {
     fn break_references(&mut self) -> bool { *self = None; true }
+    fn trace(&self, tracer: &mut Tracer) {
+        match self.as_ref() {
+            Some(r) => r.trace(tracer),
+            None => {}
+        }
+    }
+    fn trace_mut(&mut self, tracer: &mut TracerMut) {
+        match self.as_mut() {
+            Some(r) => r.trace_mut(tracer),
+            None => {}
+        }
+    }
     fn get_references(&self) -> RefList { self.as_ref().and_then(|r| r.get_references()) }
     fn get_id(&self) -> Option<uint> { self.as_ref().and_then(|r| r.get_id()) }
+    fn strong_count(&self) -> Option<uint> { self.as_ref().and_then(|r| r.strong_count()) }
+}
+
+/// A `Box` owns its contents exclusively, so it forwards straight through to them.
+impl<R: CyclicReference> CyclicReference for Box<R> {
+    fn break_references(&mut self) -> bool { (**self).break_references() }
+    fn trace(&self, tracer: &mut Tracer) { (**self).trace(tracer) }
+    fn trace_mut(&mut self, tracer: &mut TracerMut) { (**self).trace_mut(tracer) }
+    fn get_references(&self) -> RefList { (**self).get_references() }
+    fn get_id(&self) -> Option<uint> { (**self).get_id() }
+    fn strong_count(&self) -> Option<uint> { (**self).strong_count() }
+}
+
+/// The trial-deletion color of an object during a cycle collection.
+///
+/// These track the state machine of Bacon and Rajan's "Concurrent Cycle Collection in Reference
+/// Counted Systems". We only use the synchronous subset here, so `Purple` is recorded for the
+/// candidate roots but never drives a separate buffering phase.
+#[deriving(PartialEq, Eq, Clone, Copy)]
+enum Color {
+    /// In use or free; not part of a cycle under consideration.
+    Black,
+    /// Possible member of a cycle; visited during `mark_gray`.
+    Gray,
+    /// Member of a garbage cycle; scheduled to have its references broken.
+    White,
+    /// Possible root of a cycle (a candidate whose count was decremented).
+    Purple,
+}
+
+/// Per-object bookkeeping for trial deletion: the object's color and its simulated strong count.
+type Trial = ::std::collections::HashMap<uint, (Color, isize)>;
+
+/// Seed `id`'s entry from the live strong count if we have not met it yet, returning whether an id
+/// was available to key on.
+fn seed(node: &CyclicReference, trial: &mut Trial) -> Option<uint> {
+    let id = match node.get_id() { None => return None, Some(id) => id };
+    if !trial.contains_key(&id) {
+        let count = node.strong_count().unwrap_or(0) as isize;
+        trial.insert(id, (Color::Black, count));
+    }
+    Some(id)
+}
+
+/// MarkGray: color the subgraph Gray and subtract out every internal edge so that a node's
+/// simulated count reflects only the references held from *outside* the candidate subgraph.
+fn mark_gray(node: &CyclicReference, trial: &mut Trial) {
+    let id = match seed(node, trial) {
+        None => return,
+        Some(id) => id,
+    };
+    if trial[id].0 == Color::Gray { return }
+    trial.get_mut(&id).unwrap().0 = Color::Gray;
+    node.trace(&mut Tracer::new(&mut |child: &CyclicReference| {
+        match seed(child, trial) {
+            Some(cid) => trial.get_mut(&cid).unwrap().1 -= 1,
+            None => {}
+        }
+        mark_gray(child, trial);
+    }));
+}
+
+/// Scan: a Gray node whose simulated count survived is externally reachable, so restore it (and its
+/// subgraph) to Black; otherwise it is provisionally garbage and is colored White.
+fn scan(node: &CyclicReference, trial: &mut Trial) {
+    let id = match node.get_id() { None => return, Some(id) => id };
+    match trial.get(&id) {
+        Some(&(Color::Gray, _)) => {}
+        _ => return,
+    }
+    if trial[id].1 > 0 {
+        scan_black(node, trial);
+    } else {
+        trial.get_mut(&id).unwrap().0 = Color::White;
+        node.trace(&mut Tracer::new(&mut |child: &CyclicReference| scan(child, trial)));
+    }
+}
+
+/// ScanBlack: undo a `mark_gray` subtraction, re-incrementing child counts and recoloring the
+/// externally-reachable subgraph Black.
+fn scan_black(node: &CyclicReference, trial: &mut Trial) {
+    let id = match node.get_id() { None => return, Some(id) => id };
+    trial.get_mut(&id).unwrap().0 = Color::Black;
+    node.trace(&mut Tracer::new(&mut |child: &CyclicReference| {
+        match child.get_id() {
+            Some(cid) => {
+                trial.get_mut(&cid).unwrap().1 += 1;
+                if trial[cid].0 != Color::Black {
+                    scan_black(child, trial);
+                }
+            }
+            None => {}
+        }
+    }));
+}
+
+/// CollectWhite: every node still White is part of an unreachable cycle. Recurse first, then break
+/// this node's references, counting it.
+fn collect_white(node: &mut CyclicReference, trial: &mut Trial, broken: &mut u32) {
+    let id = match node.get_id() { None => return, Some(id) => id };
+    match trial.get(&id) {
+        Some(&(Color::White, _)) => {}
+        _ => return,
+    }
+    trial.get_mut(&id).unwrap().0 = Color::Black;
+    node.trace_mut(&mut TracerMut::new(&mut |child: &mut CyclicReference| {
+        collect_white(child, trial, broken);
+    }));
+    if node.break_references() { *broken += 1 }
+}
+
+/// Walk the subgraph reachable from `node`, recording each node's live strong count in `strong`
+/// and, in `incoming`, how many times it is referenced *from within the visited set*.
+fn walk_in_set(node: &CyclicReference,
+               visited: &mut ::std::collections::HashSet<uint>,
+               incoming: &mut ::std::collections::HashMap<uint, uint>,
+               strong: &mut ::std::collections::HashMap<uint, uint>) {
+    let id = match node.get_id() { None => return, Some(id) => id };
+    if !visited.insert(id) { return }
+    strong.insert(id, node.strong_count().unwrap_or(0));
+    node.trace(&mut Tracer::new(&mut |child: &CyclicReference| {
+        match child.get_id() {
+            Some(cid) => {
+                let seen = incoming.get(&cid).map(|n| *n).unwrap_or(0);
+                incoming.insert(cid, seen + 1);
+            }
+            None => {}
+        }
+        walk_in_set(child, visited, incoming, strong);
+    }));
 }
 
-/// Run a cycle collection, starting at `reference`, returning the number of objects collected, or
-/// None if `reference` returned `None` from either `get_id` or `get_references`.
+/// Decide whether the clique reachable from `reference` is only kept alive by itself, following
+/// CactusRef's reachability test. We compare each node's live strong count against the number of
+/// references it receives from inside the visited set: if they match for every node the clique is
+/// orphaned and safe to break, but if any node's strong count exceeds its in-set references it has
+/// an external owner and the whole clique must be left alone.
 ///
-/// This will do a depth-first search on the object graph as seen by `R::get_references`. As it
-/// walks the graph, it records the ids of objects it has already seen, via `R::get_id`. If it sees
-/// an object it has already seen, it will call `break_references` on that reference.
+/// This only holds if walking the graph doesn't itself create owners: every edge has to be
+/// discovered by borrowing the child, not cloning it, or the clone's temporary `Rc::strong_count`
+/// bump would masquerade as an external owner. `trace` implementations must borrow their children
+/// (as `Rc<RefCell<R>>`'s own does) rather than falling back to `get_references`, which clones.
+///
+/// `reference` itself is exempt from this comparison in one respect: whatever reference the caller
+/// used to invoke `collect` necessarily keeps it alive for the duration of the call, but that
+/// holder is the candidate root itself, not an external owner, so it's subtracted out of the root's
+/// count before comparing. Every other node's count is the in-set edges it should be explained by.
+fn is_orphaned(reference: &CyclicReference) -> bool {
+    use std::collections::{HashMap, HashSet};
+
+    let mut visited = HashSet::new();
+    let mut incoming = HashMap::new();
+    let mut strong = HashMap::new();
+    walk_in_set(reference, &mut visited, &mut incoming, &mut strong);
+
+    if let Some(root_id) = reference.get_id() {
+        if let Some(count) = strong.get_mut(&root_id) { *count -= 1; }
+    }
+
+    for (id, count) in strong.iter() {
+        let in_set = incoming.get(id).map(|n| *n).unwrap_or(0);
+        if *count > in_set { return false }
+    }
+    true
+}
+
+/// Run a cycle collection rooted at `reference`, returning the number of objects whose references
+/// were broken, or `None` if `reference` has no id to key the trial-deletion state off of.
+///
+/// This is the synchronous variant of Bacon and Rajan's trial-deletion cycle collector. Treating
+/// `reference` as a candidate root, it makes three passes over the subgraph reached through
+/// `trace` (keyed by `get_id`): `mark_gray` colors the subgraph and subtracts out its
+/// internal edges from a set of simulated strong counts seeded from `Rc::strong_count`; `scan`
+/// then restores any node whose count survived (it has an external owner) to Black and leaves the
+/// rest White; and `collect_white` breaks every node that is still White. Because internal cycle
+/// edges are subtracted out, shared subgraphs that remain externally reachable keep a positive
+/// count and are left untouched, so we only break genuine garbage cycles.
+///
+/// As a further guard, before breaking anything we check that the clique is actually orphaned: if
+/// any node's live strong count exceeds the references it receives from inside the visited set it
+/// has an external owner, and `collect` returns `Some(0)` without breaking a thing. This makes it
+/// safe to run speculatively on any candidate.
 ///
 /// For this to be effective with, for example, `Rc`, the system using this to perform cycle
 /// collection should store a list of weak pointers to every object in the system, and periodically
 /// remove weak pointers to destroyed objects. Any remaining pointers in the list would then be a
 /// good candidate for cycle collection.
 pub fn collect<R>(reference: &mut R) -> Option<u32> where R : CyclicReference {
-    // reconsider this choice of set after profiling
-    use std::collections::BTreeSet;
+    if reference.get_id().is_none() {
+        return None;
+    }
 
-    let mut seen = BTreeSet::new();
-    let mut to_visit = Vec::new();
+    // Speculative-safety guard: if anything outside the clique still owns one of these objects,
+    // breaking the cycle would corrupt a live subgraph, so refuse to touch it.
+    if !is_orphaned(reference) {
+        return Some(0);
+    }
+
+    let mut trial = Trial::new();
     let mut broken = 0;
 
-    seen.insert(match reference.get_id() {
+    // The root is the candidate whose count we suspect a cyclic edge of propping up. As in
+    // `is_orphaned`, its seeded count includes the caller's own holding reference, which isn't an
+    // internal edge `mark_gray` will ever subtract out, so correct for it here too: otherwise the
+    // root's simulated count never reaches zero and `scan` always recolors the whole subgraph Black.
+    seed(reference, &mut trial);
+    match reference.get_id() {
+        Some(id) => {
+            trial.get_mut(&id).unwrap().0 = Color::Purple;
+            trial.get_mut(&id).unwrap().1 -= 1;
+        }
         None => return None,
-        Some(id) => id
-    });
+    }
 
-    match reference.get_references() {
-        None => return None,
-        Some(refs) => to_visit.extend(refs.into_iter())
-    }
-
-    while !to_visit.is_empty() {
-        let mut refe = to_visit.pop().expect("to_visit was empty but we just checked it wasn't!?");
-        if seen.insert(match refe.get_id() {
-            None => continue,
-            Some(id) => id
-        }) {
-            if refe.break_references() { broken += 1 }
-            match refe.get_references() {
-                None => continue,
-                Some(refs) => to_visit.extend(refs.into_iter())
+    mark_gray(reference, &mut trial);
+    scan(reference, &mut trial);
+    collect_white(reference, &mut trial, &mut broken);
+
+    Some(broken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CyclicReference, Tracer, TracerMut, collect};
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use self::Link::{Nil, Next};
+
+    type Node = Rc<RefCell<Link>>;
+
+    /// A minimal traceable list cell, borrowing its child the same way `examples/simple.rs`'s
+    /// `List` does, so these tests exercise the same borrowing-not-cloning contract `is_orphaned`
+    /// depends on.
+    enum Link {
+        Nil,
+        Next(Node),
+    }
+
+    impl CyclicReference for Link {
+        fn trace(&self, tracer: &mut Tracer) {
+            match *self {
+                Next(ref n) => tracer.visit(n),
+                Nil => {}
             }
         }
+
+        fn trace_mut(&mut self, tracer: &mut TracerMut) {
+            match *self {
+                Next(ref mut n) => tracer.visit(n),
+                Nil => {}
+            }
+        }
+
+        fn break_references(&mut self) -> bool {
+            *self = Nil;
+            true
+        }
+
+        fn get_id(&self) -> Option<uint> { None }
     }
 
-    Some(broken)
+    fn mk(link: Link) -> Node {
+        Rc::new(RefCell::new(link))
+    }
+
+    #[test]
+    fn collects_a_self_cycle_with_no_external_owner() {
+        let mut a = mk(Nil);
+        *a.borrow_mut() = Next(a.clone());
+
+        // `a` is a single allocation holding a self-reference; only `a`'s own binding and that
+        // self-edge keep it alive, so once the cycle is recognized as orphaned there is exactly one
+        // object to break.
+        assert_eq!(collect(&mut a), Some(1));
+    }
+
+    #[test]
+    fn leaves_an_externally_referenced_cycle_alone() {
+        let mut a = mk(Nil);
+        *a.borrow_mut() = Next(a.clone());
+
+        // Keep an extra handle alive for the duration of the test: `a` is no longer orphaned, so
+        // `collect` must refuse to break anything.
+        let _kept_alive = a.clone();
+        assert_eq!(collect(&mut a), Some(0));
+    }
+
+    #[test]
+    fn collects_a_two_node_cycle_via_trace_mut() {
+        let mut a = mk(Nil);
+        let b = mk(Nil);
+        *a.borrow_mut() = Next(b.clone());
+        *b.borrow_mut() = Next(a.clone());
+        // Drop `b`'s own binding: only the cycle `a` <-> `b` keeps `b` alive now, so this exercises
+        // `collect_white` actually reaching past the root via `trace_mut` to break `b` too, instead
+        // of relying on ordinary `Rc` drop to cascade once `a` is broken.
+        drop(b);
+
+        assert_eq!(collect(&mut a), Some(2));
+    }
 }